@@ -1,39 +1,490 @@
 // Prevents additional console window on Windows in release builds
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use tauri::{Manager, Window};
-use std::process::{Command, Child};
-use std::sync::Mutex;
+#[cfg(debug_assertions)]
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+#[cfg(debug_assertions)]
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::api::process::{Command as SidecarCommand, CommandChild, CommandEvent};
+use tauri::{
+    CustomMenuItem, Manager, RunEvent, SystemTray, SystemTrayEvent, SystemTrayMenu,
+    SystemTrayMenuItem, Window, WindowEvent,
+};
+
+/// A single line read from the server's stdout/stderr, emitted as a `server-log` event.
+#[derive(Clone, Serialize)]
+struct LogLine {
+    stream: &'static str,
+    text: String,
+}
+
+/// Maximum number of consecutive respawn attempts before the supervisor gives up.
+const MAX_RESPAWN_ATTEMPTS: u32 = 5;
+
+/// Env var letting a debug build force the Python fallback (and optionally point it at
+/// a non-default interpreter) instead of trying the sidecar first.
+#[cfg(debug_assertions)]
+const PYTHON_PATH_ENV: &str = "MAPTOPOSTER_PYTHON";
+
+/// How long to wait for the server to start accepting connections before giving up.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The managed server process. Release builds always use the bundled `run-local`
+/// sidecar; debug builds try the sidecar first and fall back to invoking the runner
+/// script directly with a system (or `MAPTOPOSTER_PYTHON`-configured) interpreter if
+/// it isn't built locally, or if `MAPTOPOSTER_PYTHON` asks for that fallback outright.
+enum ManagedChild {
+    // The `CommandEvent` receiver is drained on its own thread, which flips `alive`
+    // to false once it sees `CommandEvent::Terminated`; there's no `try_wait` for a
+    // sidecar, so this is how the supervisor notices it went away.
+    Sidecar(CommandChild, Arc<AtomicBool>),
+    #[cfg(debug_assertions)]
+    Python(std::process::Child),
+}
+
+impl ManagedChild {
+    fn kill(self) {
+        match self {
+            ManagedChild::Sidecar(child, _alive) => {
+                let _ = child.kill();
+            }
+            #[cfg(debug_assertions)]
+            ManagedChild::Python(mut child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    fn is_alive(&mut self) -> bool {
+        match self {
+            ManagedChild::Sidecar(_, alive) => alive.load(Ordering::SeqCst),
+            #[cfg(debug_assertions)]
+            ManagedChild::Python(child) => matches!(child.try_wait(), Ok(None)),
+        }
+    }
+}
 
 // Store the server process
-struct ServerProcess(Mutex<Option<Child>>);
+struct ServerProcess(Mutex<Option<ManagedChild>>);
 
-#[tauri::command]
-fn start_server(window: Window) -> Result<String, String> {
-    // Start the Python server
-    let server = Command::new("python3")
+// The port the currently-managed server is listening on, so other commands
+// (and the frontend, via `server_url`) can discover it.
+struct ServerPort(Mutex<Option<u16>>);
+
+// Bumped every time `start_server` installs a new child. A `supervise` thread
+// captures the generation it was spawned for and retires as soon as it no
+// longer matches, so a restart can never leave two supervisors watching the
+// same slot.
+struct ServerGeneration(AtomicU64);
+
+/// Binds an ephemeral port and immediately releases it so the server can bind it instead.
+fn allocate_port() -> std::io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    listener.local_addr().map(|addr| addr.port())
+}
+
+/// Polls `127.0.0.1:<port>` until it accepts a connection or `READY_TIMEOUT` elapses.
+fn wait_until_ready(port: u16) -> bool {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    while Instant::now() < deadline {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+/// Spawns reader threads that forward each line of `stdout`/`stderr` to the window
+/// as `server-log` events. Only used by the debug-only Python fallback; the sidecar
+/// path streams output via `CommandEvent` instead (see `stream_sidecar_events`).
+#[cfg(debug_assertions)]
+fn stream_output(window: &Window, stdout: impl std::io::Read + Send + 'static, stream: &'static str) {
+    let window = window.clone();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let _ = window.emit("server-log", LogLine { stream, text: line });
+        }
+    });
+}
+
+#[cfg(debug_assertions)]
+fn spawn_python(window: &Window, port: u16) -> std::io::Result<std::process::Child> {
+    let interpreter = std::env::var(PYTHON_PATH_ENV).unwrap_or_else(|_| "python3".to_string());
+    let mut child = std::process::Command::new(interpreter)
         .arg("universal-ui/runner/run_local.py")
         .arg("--serve")
+        .arg("--port")
+        .arg(port.to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    stream_output(window, child.stdout.take().unwrap(), "stdout");
+    stream_output(window, child.stderr.take().unwrap(), "stderr");
+
+    Ok(child)
+}
+
+/// Reads `CommandEvent`s from a spawned sidecar, forwarding stdout/stderr lines as
+/// `server-log` events and clearing `alive` once the process terminates.
+fn stream_sidecar_events(
+    window: Window,
+    mut rx: tauri::async_runtime::Receiver<CommandEvent>,
+    alive: Arc<AtomicBool>,
+) {
+    tauri::async_runtime::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            match event {
+                CommandEvent::Stdout(line) => {
+                    let _ = window.emit(
+                        "server-log",
+                        LogLine {
+                            stream: "stdout",
+                            text: line,
+                        },
+                    );
+                }
+                CommandEvent::Stderr(line) => {
+                    let _ = window.emit(
+                        "server-log",
+                        LogLine {
+                            stream: "stderr",
+                            text: line,
+                        },
+                    );
+                }
+                CommandEvent::Terminated(_) | CommandEvent::Error(_) => {
+                    alive.store(false, Ordering::SeqCst);
+                    return;
+                }
+                _ => {}
+            }
+        }
+        alive.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Spawns the managed server process on `port`: the bundled `run-local` sidecar, which
+/// is what release builds always use. Debug builds try the sidecar first too (it may
+/// well be built locally), but since it usually isn't present during day-to-day
+/// development, a missing/unspawnable sidecar there falls back to invoking the runner
+/// script directly with a system (or `MAPTOPOSTER_PYTHON`-configured) interpreter.
+fn spawn_server(window: &Window, port: u16) -> Result<ManagedChild, String> {
+    #[cfg(debug_assertions)]
+    {
+        if std::env::var(PYTHON_PATH_ENV).is_ok() {
+            let child =
+                spawn_python(window, port).map_err(|e| format!("Failed to start server: {}", e))?;
+            return Ok(ManagedChild::Python(child));
+        }
+
+        match spawn_sidecar(window, port) {
+            Ok(managed) => return Ok(managed),
+            Err(_) => {
+                let child = spawn_python(window, port)
+                    .map_err(|e| format!("Failed to start server: {}", e))?;
+                return Ok(ManagedChild::Python(child));
+            }
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    spawn_sidecar(window, port)
+}
+
+/// Resolves and spawns the bundled `run-local` sidecar.
+fn spawn_sidecar(window: &Window, port: u16) -> Result<ManagedChild, String> {
+    let (rx, child) = SidecarCommand::new_sidecar("run-local")
+        .map_err(|e| format!("Failed to resolve server sidecar: {}", e))?
+        .args(["--serve", "--port", &port.to_string()])
         .spawn()
         .map_err(|e| format!("Failed to start server: {}", e))?;
-    
-    // Store the process
+
+    let alive = Arc::new(AtomicBool::new(true));
+    stream_sidecar_events(window.clone(), rx, alive.clone());
+
+    Ok(ManagedChild::Sidecar(child, alive))
+}
+
+/// Kills the currently-managed server process, if any. The Python fallback is also
+/// waited on; a sidecar is only killed, since `CommandChild` offers no wait.
+fn kill_server(state: &ServerProcess) {
+    if let Some(child) = state.0.lock().unwrap().take() {
+        child.kill();
+    }
+}
+
+/// Watches the managed child in the background. If it exits unexpectedly (i.e. not
+/// because `stop_server`/shutdown already cleared the slot), emits `server-crashed`
+/// and respawns with exponential backoff, up to `MAX_RESPAWN_ATTEMPTS` consecutive
+/// failures. `generation` is the value `ServerGeneration` held when this supervisor
+/// was spawned; once a newer `start_server` bumps it, this thread retires instead of
+/// racing the new supervisor over the same `ServerProcess` slot.
+fn supervise(window: Window, generation: u64) {
+    thread::spawn(move || {
+        let mut attempt: u32 = 0;
+        // Set after a respawn fails, so the next pass retries instead of
+        // reading the (now empty) slot as "stopped".
+        let mut retrying = false;
+        loop {
+            thread::sleep(Duration::from_millis(500));
+
+            let gen_state: tauri::State<ServerGeneration> = window.state();
+            if gen_state.0.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            let exited = retrying || {
+                let state: tauri::State<ServerProcess> = window.state();
+                let mut guard = state.0.lock().unwrap();
+                match guard.as_mut() {
+                    // Slot was cleared by stop_server/shutdown: nothing to supervise.
+                    None => return,
+                    Some(child) => {
+                        let alive = child.is_alive();
+                        if !alive {
+                            *guard = None;
+                        }
+                        !alive
+                    }
+                }
+            };
+
+            if !exited {
+                continue;
+            }
+
+            if !retrying {
+                let _ = window.emit("server-crashed", ());
+            }
+
+            if attempt >= MAX_RESPAWN_ATTEMPTS {
+                let _ = window.emit("server-status", "crashed");
+                return;
+            }
+
+            thread::sleep(Duration::from_secs(1 << attempt.min(4)));
+            attempt += 1;
+
+            let port_state: tauri::State<ServerPort> = window.state();
+            let port = match *port_state.0.lock().unwrap() {
+                Some(port) => port,
+                None => return,
+            };
+
+            let _ = window.emit("server-status", "starting");
+
+            match spawn_server(&window, port) {
+                Ok(child) if wait_until_ready(port) => {
+                    let gen_state: tauri::State<ServerGeneration> = window.state();
+                    let state: tauri::State<ServerProcess> = window.state();
+                    let mut guard = state.0.lock().unwrap();
+                    // Re-check the generation under the lock before inserting: Stop
+                    // bumps it to mean "nothing should be resurrected", and Restart
+                    // bumps it when `start_server` installs its own child. Either
+                    // way a stale generation here means inserting would resurrect a
+                    // server the user just stopped, or silently leak a newer one.
+                    if gen_state.0.load(Ordering::SeqCst) != generation {
+                        drop(guard);
+                        child.kill();
+                        return;
+                    }
+                    *guard = Some(child);
+                    attempt = 0;
+                    retrying = false;
+                    drop(guard);
+                    let _ = window.emit("server-status", "ready");
+                }
+                Ok(child) => {
+                    child.kill();
+                    retrying = true;
+                }
+                Err(_) => retrying = true,
+            }
+        }
+    });
+}
+
+#[tauri::command]
+fn start_server(window: Window) -> Result<String, String> {
+    let state: tauri::State<ServerProcess> = window.state();
+    let gen_state: tauri::State<ServerGeneration> = window.state();
+
+    // Hold the lock across the check-and-insert so a setup auto-start racing a
+    // frontend `invoke("start_server")` can't both pass the guard and spawn a
+    // second process.
+    let mut guard = state.0.lock().unwrap();
+    let live = guard.as_mut().map(ManagedChild::is_alive).unwrap_or(false);
+    if live {
+        let port_state: tauri::State<ServerPort> = window.state();
+        let url = port_state
+            .0
+            .lock()
+            .unwrap()
+            .map(|port| format!("http://127.0.0.1:{}", port))
+            .unwrap_or_else(|| "Server already running".to_string());
+        return Ok(url);
+    }
+
+    let _ = window.emit("server-status", "starting");
+
+    let port = allocate_port().map_err(|e| format!("Failed to allocate a port: {}", e))?;
+
+    let server = spawn_server(&window, port)?;
+    *guard = Some(server);
+    let generation = gen_state.0.fetch_add(1, Ordering::SeqCst) + 1;
+    drop(guard);
+
+    if !wait_until_ready(port) {
+        kill_server(&state);
+        let _ = window.emit("server-status", "crashed");
+        return Err("Server did not become ready in time".to_string());
+    }
+
+    let port_state: tauri::State<ServerPort> = window.state();
+    *port_state.0.lock().unwrap() = Some(port);
+
+    supervise(window.clone(), generation);
+
+    let _ = window.emit("server-status", "ready");
+    Ok(format!("http://127.0.0.1:{}", port))
+}
+
+/// Runs `start_server` on a worker thread instead of the caller's thread. `start_server`
+/// blocks for up to `READY_TIMEOUT` inside `wait_until_ready`, so callers that run on the
+/// UI/event thread (`.setup()`, tray menu handlers) must not call it directly — readiness
+/// is reported via the existing `server-status` events instead of this call's return value.
+fn start_server_async(window: Window) {
+    thread::spawn(move || {
+        let _ = start_server(window);
+    });
+}
+
+/// Kills the managed child, clears the stored port, and emits `server-status: stopped`.
+/// The single teardown path shared by `stop_server`, the tray's stop/restart/quit
+/// actions, and a real window destroy, so state and events never drift between them.
+/// Also bumps `ServerGeneration`, so a supervisor or in-flight respawn that was
+/// watching the torn-down child retires instead of resurrecting it.
+fn stop_and_clear(window: &Window) {
     let state: tauri::State<ServerProcess> = window.state();
-    *state.0.lock().unwrap() = Some(server);
-    
-    Ok("Server started".to_string())
+    kill_server(&state);
+    let port_state: tauri::State<ServerPort> = window.state();
+    *port_state.0.lock().unwrap() = None;
+    let gen_state: tauri::State<ServerGeneration> = window.state();
+    gen_state.0.fetch_add(1, Ordering::SeqCst);
+    let _ = window.emit("server-status", "stopped");
+}
+
+#[tauri::command]
+fn stop_server(window: Window) -> Result<(), String> {
+    stop_and_clear(&window);
+    Ok(())
+}
+
+/// Returns the base URL of the currently-running server, if any.
+#[tauri::command]
+fn server_url(window: Window) -> Option<String> {
+    let port_state: tauri::State<ServerPort> = window.state();
+    port_state
+        .0
+        .lock()
+        .unwrap()
+        .map(|port| format!("http://127.0.0.1:{}", port))
+}
+
+fn build_tray() -> SystemTray {
+    let menu = SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new("start", "Start Server"))
+        .add_item(CustomMenuItem::new("stop", "Stop Server"))
+        .add_item(CustomMenuItem::new("restart", "Restart Server"))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("show", "Show Window"))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("quit", "Quit"));
+
+    SystemTray::new().with_menu(menu)
+}
+
+fn on_tray_event(app: &tauri::AppHandle, event: SystemTrayEvent) {
+    let SystemTrayEvent::MenuItemClick { id, .. } = event else {
+        return;
+    };
+    // The window is hidden (not destroyed) on close, so this should always resolve;
+    // bail out rather than panic if some other path ever does tear it down.
+    let Some(window) = app.get_window("main") else {
+        return;
+    };
+
+    match id.as_str() {
+        "start" => {
+            // Off the tray event thread: start_server blocks on the readiness
+            // check and would otherwise freeze the whole app for up to
+            // READY_TIMEOUT.
+            start_server_async(window);
+        }
+        "stop" => {
+            stop_and_clear(&window);
+        }
+        "restart" => {
+            stop_and_clear(&window);
+            start_server_async(window);
+        }
+        "show" => {
+            let _ = window.show();
+            let _ = window.set_focus();
+        }
+        "quit" => {
+            // Route through the same teardown as a window destroy so the
+            // server never outlives the tray.
+            stop_and_clear(&window);
+            app.exit(0);
+        }
+        _ => {}
+    }
 }
 
 fn main() {
     tauri::Builder::default()
         .manage(ServerProcess(Mutex::new(None)))
-        .invoke_handler(tauri::generate_handler![start_server])
+        .manage(ServerPort(Mutex::new(None)))
+        .manage(ServerGeneration(AtomicU64::new(0)))
+        .invoke_handler(tauri::generate_handler![start_server, stop_server, server_url])
         .setup(|app| {
-            // Optionally auto-start server on app launch
+            // Optionally auto-start server on app launch. Spawned off the setup
+            // thread since start_server blocks on the readiness check and setup
+            // runs synchronously before the app can present a window.
             let window = app.get_window("main").unwrap();
-            let _ = start_server(window);
+            start_server_async(window);
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .on_window_event(|event| match event.event() {
+            // Keep the app (and its server) resident in the tray instead of
+            // tearing down on close; "Show Window" relies on the window still
+            // existing to un-hide.
+            WindowEvent::CloseRequested { api, .. } => {
+                api.prevent_close();
+                let _ = event.window().hide();
+            }
+            WindowEvent::Destroyed => stop_and_clear(event.window()),
+            _ => {}
+        })
+        .system_tray(build_tray())
+        .on_system_tray_event(on_tray_event)
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                let state: tauri::State<ServerProcess> = app_handle.state();
+                kill_server(&state);
+            }
+        });
 }